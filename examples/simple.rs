@@ -1,6 +1,3 @@
-#![feature(alloc_system)]
-extern crate alloc_system;
-
 extern crate owning_bytes;
 
 use owning_bytes::OwningByteBuf;
@@ -29,7 +26,7 @@ fn read() -> Vec<u8> {
 }
 
 // Example read + parse step
-fn get_next_parsed() -> OwningByteBuf<ExampleParsed<'static>> {
+fn get_next_parsed() -> OwningByteBuf<Vec<u8>, ExampleParsed<'static>> {
     let vec = read();
 
     OwningByteBuf::from_vec(vec, ExampleParsed::parse_buf)
@@ -1,5 +1,7 @@
-#![feature(unique, alloc, heap_api, question_mark)]
 #![warn(missing_docs)]
+// The struct-literal style throughout this crate predates `redundant_field_names`, and the
+// fallible constructors' `Result<Self, (E, O)>` shape is intentional, not accidental complexity.
+#![allow(clippy::redundant_field_names, clippy::type_complexity)]
 
 //! # Owning bytes
 //!
@@ -22,7 +24,7 @@
 //! }
 //! }
 //!
-//! fn create_from_vec(vec: Vec<u8>) -> OwningByteBuf<ExampleParsed<'static>> {
+//! fn create_from_vec(vec: Vec<u8>) -> OwningByteBuf<Vec<u8>, ExampleParsed<'static>> {
 //! OwningByteBuf::from_vec(vec, ExampleParsed::parse_buf)
 //! }
 //!
@@ -42,7 +44,7 @@
 //! use std::str::{self, Utf8Error};
 //!
 //!
-//! fn create_from_vec(vec: Vec<u8>) -> Result<OwningByteBuf<&'static str>, Utf8Error> {
+//! fn create_from_vec(vec: Vec<u8>) -> Result<OwningByteBuf<Vec<u8>, &'static str>, Utf8Error> {
 //! OwningByteBuf::from_vec_res(vec, str::from_utf8).map_err(|(err, _vec)| err)
 //! }
 //!
@@ -55,44 +57,154 @@
 //! }
 //! ```
 //!
+//! `OwningByteBuf` is generic over the owner of the underlying bytes, so it isn't limited to
+//! `Vec<u8>`: anything implementing [`StableAddress`](trait.StableAddress.html), such as
+//! `Box<[u8]>`, `Rc<[u8]>` or `Arc<[u8]>`, can be used instead.
 
-extern crate alloc;
+extern crate bytes;
+#[cfg(feature = "core_io")]
+extern crate core_io;
 
-use std::mem;
-use std::ptr::Unique;
+use std::cmp;
+use std::ops::Deref;
+use std::rc::Rc;
 use std::slice;
-use alloc::heap;
+use std::sync::Arc;
 
 use std::convert::AsRef;
 
-/// A wrapper around an array of bytes and an object T that references those bytes.
-pub struct OwningByteBuf<T> {
-    resource: Unique<u8>,
-    len: usize,
-    cap: usize,
+use bytes::Buf;
+
+#[cfg(not(feature = "core_io"))]
+use std::io;
+#[cfg(feature = "core_io")]
+use core_io as io;
+
+#[cfg(not(feature = "core_io"))]
+use std::io::{Read, Seek, SeekFrom};
+#[cfg(feature = "core_io")]
+use core_io::{Read, Seek, SeekFrom};
+
+/// A marker for owners of a byte buffer whose address is stable, i.e. it does not change when the
+/// owner itself is moved.
+///
+/// # Safety
+///
+/// Implementors must guarantee that `deref()` always returns a slice backed by the same memory
+/// for as long as the owner lives, even if the owner is moved. This is what allows
+/// `OwningByteBuf` to hand out a reference into that memory that outlives the borrow of the
+/// owner used to create it.
+pub unsafe trait StableAddress: Deref<Target = [u8]> {}
+
+unsafe impl StableAddress for Vec<u8> {}
+unsafe impl StableAddress for Box<[u8]> {}
+unsafe impl StableAddress for Rc<[u8]> {}
+unsafe impl StableAddress for Arc<[u8]> {}
+
+/// A wrapper around an owner of an array of bytes, `O`, and an object `T` that references those
+/// bytes.
+pub struct OwningByteBuf<O, T> {
+    owner: O,
     inner: T,
 }
 
-impl<T> OwningByteBuf<T> {
-    /// Creates an OwningByteBuf from a vector and a constructing function
-    pub fn from_vec<'a, F>(mut buf: Vec<u8>, f: F) -> OwningByteBuf<T>
+impl<O, T> OwningByteBuf<O, T>
+    where O: StableAddress
+{
+    /// Creates an OwningByteBuf from an owner of a byte buffer and a constructing function.
+    pub fn new<'a, F>(owner: O, f: F) -> OwningByteBuf<O, T>
         where F: FnOnce(&'a [u8]) -> T
     {
-        let res = unsafe {
-            let ptr = buf.as_mut_ptr();
-            let len = buf.len();
-            let cap = buf.capacity();
-            let inner = f(slice::from_raw_parts(ptr, len));
-
-            OwningByteBuf {
-                resource: Unique::new(ptr),
-                len: len,
-                cap: cap,
-                inner: inner,
+        let inner = f(unsafe { extend_lifetime(&owner) });
+        OwningByteBuf {
+            owner: owner,
+            inner: inner,
+        }
+    }
+
+    /// Creates an OwningByteBuf from an owner of a byte buffer and a constructing function that
+    /// may fail.
+    pub fn new_res<'a, F, E>(owner: O, f: F) -> Result<OwningByteBuf<O, T>, (E, O)>
+        where F: FnOnce(&'a [u8]) -> Result<T, E>
+    {
+        match f(unsafe { extend_lifetime(&owner) }) {
+            Ok(inner) => {
+                Ok(OwningByteBuf {
+                    owner: owner,
+                    inner: inner,
+                })
             }
-        };
-        mem::forget(buf);
-        res
+            Err(e) => Err((e, owner)),
+        }
+    }
+
+    /// Returns a reference to the wrapped type.
+    pub fn get(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped type.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the wrapped type and replaces it with the result of applying `f` to it, keeping
+    /// the underlying buffer alive.
+    ///
+    /// This lets callers progressively refine a zero-copy parse (e.g. narrow a whole-packet view
+    /// down to a single header field) without having to give up the owning buffer.
+    ///
+    /// ```
+    /// use owning_bytes::OwningByteBuf;
+    ///
+    /// let vec = vec![0, 1, 2, 3];
+    /// let buf = OwningByteBuf::from_vec(vec, |buf| &buf[0..2]);
+    /// let buf = buf.map(|slice| &slice[1..]);
+    /// assert_eq!(*buf.get(), &[1]);
+    /// ```
+    pub fn map<U, F>(self, f: F) -> OwningByteBuf<O, U>
+        where F: FnOnce(T) -> U
+    {
+        let OwningByteBuf { owner, inner } = self;
+
+        OwningByteBuf {
+            owner: owner,
+            inner: f(inner),
+        }
+    }
+
+    /// Drops the wrapped type and returns the underlying owner of the buffer.
+    pub fn into_owner(self) -> O {
+        let OwningByteBuf { owner, .. } = self;
+        owner
+    }
+
+    /// Returns a cursor over the raw bytes backing this buffer that implements `bytes::Buf`,
+    /// letting the buffer be consumed by `bytes`-based writers and codecs without copying the
+    /// payload out via `into_owner`.
+    pub fn as_buf(&self) -> OwningBytesBuf<'_> {
+        OwningBytesBuf {
+            bytes: self.owner.deref(),
+            pos: 0,
+        }
+    }
+
+    /// Returns a seekable reader over the raw bytes backing this buffer, letting callers
+    /// incrementally re-scan an owned, already-parsed buffer without exposing raw pointers.
+    pub fn reader(&self) -> OwningByteReader<'_> {
+        OwningByteReader {
+            bytes: self.owner.deref(),
+            pos: 0,
+        }
+    }
+}
+
+impl<T> OwningByteBuf<Vec<u8>, T> {
+    /// Creates an OwningByteBuf from a vector and a constructing function
+    pub fn from_vec<'a, F>(buf: Vec<u8>, f: F) -> OwningByteBuf<Vec<u8>, T>
+        where F: FnOnce(&'a [u8]) -> T
+    {
+        OwningByteBuf::new(buf, f)
     }
 
     /// Creates an OwningByteBuf from a vector and a constructing function that may fail
@@ -105,108 +217,209 @@ impl<T> OwningByteBuf<T> {
     /// let string = OwningByteBuf::from_vec_res(vec, str::from_utf8).unwrap();
     /// assert_eq!(*string.get(), "Hello World");
     /// ```
-    pub fn from_vec_res<'a, F, E>(mut buf: Vec<u8>, f: F) -> Result<OwningByteBuf<T>, (E, Vec<u8>)>
+    pub fn from_vec_res<'a, F, E>(buf: Vec<u8>,
+                                  f: F)
+                                  -> Result<OwningByteBuf<Vec<u8>, T>, (E, Vec<u8>)>
         where F: FnOnce(&'a [u8]) -> Result<T, E>
     {
-        let res = unsafe {
-            let ptr = buf.as_mut_ptr();
-            let len = buf.len();
-            let cap = buf.capacity();
-            OwningByteBuf {
-                resource: Unique::new(ptr),
-                len: len,
-                cap: cap,
-                inner: match f(slice::from_raw_parts(ptr, len)) {
-                    Ok(t) => t,
-                    Err(e) => return Err((e, buf)),
-                },
-            }
-        };
-        mem::forget(buf);
-        Ok(res)
+        OwningByteBuf::new_res(buf, f)
+    }
+
+    /// Drops the wrapped type and returns the underlying buffer back as a Vec.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.into_owner()
     }
+}
 
+impl<T> OwningByteBuf<Box<[u8]>, T> {
     /// Creates an OwningByteBuf from a boxed slice and a constructing function
-    pub fn from_box<'a, F>(mut buf: Box<[u8]>, f: F) -> OwningByteBuf<T>
+    pub fn from_box<'a, F>(buf: Box<[u8]>, f: F) -> OwningByteBuf<Box<[u8]>, T>
         where F: FnOnce(&'a [u8]) -> T
     {
-        let res = unsafe {
-            let ptr = buf.as_mut_ptr();
-            let len = buf.len();
-            OwningByteBuf {
-                resource: Unique::new(ptr),
-                len: len,
-                cap: len,
-                inner: f(slice::from_raw_parts(ptr, len)),
-            }
-        };
-        mem::forget(buf);
-        res
+        OwningByteBuf::new(buf, f)
     }
 
     /// Creates an OwningByteBuf from a boxed slice and a constructing function that may fail
-    pub fn from_box_res<'a, F, E>(mut buf: Box<[u8]>,
+    pub fn from_box_res<'a, F, E>(buf: Box<[u8]>,
                                   f: F)
-                                  -> Result<OwningByteBuf<T>, (E, Box<[u8]>)>
+                                  -> Result<OwningByteBuf<Box<[u8]>, T>, (E, Box<[u8]>)>
         where F: FnOnce(&'a [u8]) -> Result<T, E>
     {
-        let res = unsafe {
-            let ptr = buf.as_mut_ptr();
-            let len = buf.len();
-            OwningByteBuf {
-                resource: Unique::new(ptr),
-                len: len,
-                cap: len,
-                inner: match f(slice::from_raw_parts(ptr, len)) {
-                    Ok(t) => t,
-                    Err(e) => return Err((e, buf)),
-                },
-            }
-        };
-        mem::forget(buf);
-        Ok(res)
+        OwningByteBuf::new_res(buf, f)
     }
+}
 
-    /// Returns a reference to the wrapped type.
-    pub fn get(&self) -> &T {
-        &self.inner
+/// A reference-counted, cheaply cloneable `OwningByteBuf` backed by `Arc<[u8]>`.
+///
+/// Cloning a `SharedOwningBytes` only bumps the `Arc`'s refcount and never copies the payload, so
+/// multiple parsed views can co-own one network read.
+pub type SharedOwningBytes<T> = OwningByteBuf<Arc<[u8]>, T>;
+
+impl<T> OwningByteBuf<Arc<[u8]>, T> {
+    /// Creates a SharedOwningBytes from an `Arc<[u8]>` and a constructing function.
+    pub fn from_arc<'a, F>(buf: Arc<[u8]>, f: F) -> OwningByteBuf<Arc<[u8]>, T>
+        where F: FnOnce(&'a [u8]) -> T
+    {
+        OwningByteBuf::new(buf, f)
     }
 
-    /// Drops the wrapped type and returns the underlying buffer back as a Vec.
-    pub fn into_vec(mut self) -> Vec<u8> {
-        let vec = {
-            let OwningByteBuf { ref resource, len, cap, .. } = self;
+    /// Given a `subset` slice that was derived from the bytes owned by this buffer, returns a
+    /// new shared handle covering just that subrange, without copying the payload.
+    ///
+    /// Returns `None` if `subset` does not lie within the owned allocation. An empty `subset` is
+    /// always treated as a valid, zero-length range at the start of the owned region, matching
+    /// the fix shipped in `bytes` 0.5.4.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use owning_bytes::SharedOwningBytes;
+    ///
+    /// let arc: Arc<[u8]> = Arc::from(vec![0, 1, 2, 3].into_boxed_slice());
+    /// let buf: SharedOwningBytes<()> = SharedOwningBytes::from_arc(arc.clone(), |_| ());
+    ///
+    /// let subset = &arc[1..3];
+    /// let sliced = buf.slice_ref(subset).unwrap();
+    /// assert_eq!(*sliced.get(), &[1, 2]);
+    /// ```
+    pub fn slice_ref(&self, subset: &[u8]) -> Option<OwningByteBuf<Arc<[u8]>, &'static [u8]>> {
+        // An empty subset's pointer isn't guaranteed to be meaningful (e.g. it may be a dangling
+        // sentinel), so it's always treated as a valid, zero-length range at the base of the
+        // owned region, rather than bounds-checked against its pointer.
+        if subset.is_empty() {
+            return Some(OwningByteBuf {
+                owner: self.owner.clone(),
+                inner: unsafe { slice::from_raw_parts(self.owner.as_ptr(), 0) },
+            });
+        }
 
-            unsafe { Vec::from_raw_parts(**resource, len, cap) }
-        };
+        let bytes: &[u8] = &self.owner;
+        let bytes_start = bytes.as_ptr() as usize;
+        let bytes_end = bytes_start + bytes.len();
+
+        let sub_start = subset.as_ptr() as usize;
+
+        if sub_start < bytes_start || sub_start + subset.len() > bytes_end {
+            return None;
+        }
 
-        self.cap = 0;
-        self.len = 0;
+        Some(OwningByteBuf {
+            owner: self.owner.clone(),
+            inner: unsafe { slice::from_raw_parts(sub_start as *const u8, subset.len()) },
+        })
+    }
+}
 
-        vec
+impl<T: Clone> Clone for OwningByteBuf<Arc<[u8]>, T> {
+    fn clone(&self) -> OwningByteBuf<Arc<[u8]>, T> {
+        OwningByteBuf {
+            owner: Arc::clone(&self.owner),
+            inner: self.inner.clone(),
+        }
     }
 }
 
-impl<T> AsRef<T> for OwningByteBuf<T> {
+impl<O, T> AsRef<T> for OwningByteBuf<O, T>
+    where O: StableAddress
+{
     fn as_ref(&self) -> &T {
         self.get()
     }
 }
 
-impl<T> Drop for OwningByteBuf<T> {
-    fn drop(&mut self) {
-        let elem_size = mem::size_of::<u8>();
-        let align = mem::align_of::<u8>();
+/// A read cursor over the raw bytes backing an `OwningByteBuf`, implementing `bytes::Buf`.
+///
+/// Obtained via `OwningByteBuf::as_buf`.
+pub struct OwningBytesBuf<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
 
-        let num_bytes = elem_size * self.cap;
-        if num_bytes > 0 {
-            unsafe {
-                heap::deallocate(*self.resource as *mut _, num_bytes, align);
+impl<'a> Buf for OwningBytesBuf<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.bytes[self.pos..]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining(), "cnt exceeds remaining bytes");
+        self.pos += cnt;
+    }
+}
+
+/// A seekable read cursor over the raw bytes backing an `OwningByteBuf`.
+///
+/// Obtained via `OwningByteBuf::reader`. Behaves exactly like `std::io::Cursor` over a byte
+/// slice. When built with the `core_io` feature this implements the `core_io` crate's `Read`
+/// and `Seek` traits instead of `std::io`'s. The crate still links `std` with or without that
+/// feature; it does not build as `no_std`.
+pub struct OwningByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Read for OwningByteReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // A prior `seek` may have landed past the end of the buffer (it only rejects negative or
+        // overflowing positions), so clamp the start of the read the same way
+        // `Cursor::remaining_slice` does, leaving `pos` itself as it was.
+        let start = cmp::min(self.pos, self.bytes.len());
+
+        let remaining = &self.bytes[start..];
+        let len = cmp::min(buf.len(), remaining.len());
+
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+impl<'a> Seek for OwningByteReader<'a> {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        let (base_pos, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.pos = n as usize;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.bytes.len() as u64, n),
+            SeekFrom::Current(n) => (self.pos as u64, n),
+        };
+
+        let new_pos = if offset >= 0 {
+            base_pos.checked_add(offset as u64)
+        } else {
+            base_pos.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n as usize;
+                Ok(n)
+            }
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    "invalid seek to a negative or overflowing position"))
             }
         }
     }
 }
 
+/// Extends the lifetime of a borrow of an owner's bytes to an arbitrary lifetime.
+///
+/// # Safety
+///
+/// This is sound only because `O: StableAddress` guarantees the returned slice stays valid, and
+/// at the same address, for as long as the owner that produced it is alive.
+unsafe fn extend_lifetime<'a, O>(owner: &O) -> &'a [u8]
+    where O: StableAddress
+{
+    let slice: &[u8] = owner.deref();
+    slice::from_raw_parts(slice.as_ptr(), slice.len())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -237,7 +450,127 @@ mod tests {
         assert_eq!(res.unwrap().get().buf, &[0, 1]);
 
         let vec = vec![0, 1, 2, 3];
-        let res: Result<OwningByteBuf<()>, _> = OwningByteBuf::from_vec_res(vec, |_| Err(()));
+        let res: Result<OwningByteBuf<Vec<u8>, ()>, _> =
+            OwningByteBuf::from_vec_res(vec, |_| Err(()));
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_get_mut() {
+        let vec = vec![0, 1, 2, 3];
+        let mut foo = OwningByteBuf::from_vec(vec, |buf| Test { buf: &buf[0..2] });
+
+        let narrowed = foo.get().buf[1..].as_ptr();
+        foo.get_mut().buf = unsafe { slice::from_raw_parts(narrowed, 1) };
+
+        assert_eq!(foo.get().buf, &[1]);
+    }
+
+    #[test]
+    fn test_map() {
+        let vec = vec![0, 1, 2, 3];
+        let foo = OwningByteBuf::from_vec(vec, |buf| Test { buf: &buf[0..2] });
+
+        let foo = foo.map(|test| test.buf);
+        assert_eq!(*foo.get(), &[0, 1]);
+
+        let vec = foo.into_vec();
+        assert_eq!(vec, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_as_buf() {
+        let vec = vec![0, 1, 2, 3];
+        let foo = OwningByteBuf::from_vec(vec, |buf| Test { buf: &buf[0..2] });
+
+        let mut buf = foo.as_buf();
+        assert_eq!(buf.remaining(), 4);
+        assert_eq!(buf.chunk(), &[0, 1, 2, 3]);
+
+        buf.advance(2);
+        assert_eq!(buf.remaining(), 2);
+        assert_eq!(buf.chunk(), &[2, 3]);
+    }
+
+    #[test]
+    fn test_reader() {
+        let vec = vec![0, 1, 2, 3];
+        let foo = OwningByteBuf::from_vec(vec, |buf| Test { buf: &buf[0..2] });
+
+        let mut reader = foo.reader();
+
+        let mut out = [0; 2];
+        reader.read_exact(&mut out).unwrap();
+        assert_eq!(out, [0, 1]);
+
+        reader.seek(SeekFrom::Start(1)).unwrap();
+        reader.read_exact(&mut out).unwrap();
+        assert_eq!(out, [1, 2]);
+
+        reader.seek(SeekFrom::End(-1)).unwrap();
+        let n = reader.read(&mut out).unwrap();
+        assert_eq!(&out[..n], &[3]);
+    }
+
+    #[test]
+    fn test_reader_seek_past_end() {
+        let vec = vec![0, 1, 2, 3];
+        let foo = OwningByteBuf::from_vec(vec, |buf| Test { buf: &buf[0..2] });
+
+        let mut reader = foo.reader();
+        reader.seek(SeekFrom::Start(100)).unwrap();
+
+        let mut out = [0; 2];
+        let n = reader.read(&mut out).unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_arc_owner() {
+        let bytes: Arc<[u8]> = Arc::from(vec![0, 1, 2, 3].into_boxed_slice());
+        let foo = OwningByteBuf::new(bytes, |buf| Test { buf: &buf[0..2] });
+
+        assert_eq!(foo.get().buf, &[0, 1]);
+
+        let owner = foo.into_owner();
+        assert_eq!(&*owner, &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_rc_owner() {
+        let bytes: Rc<[u8]> = Rc::from(vec![0, 1, 2, 3].into_boxed_slice());
+        let foo = OwningByteBuf::new(bytes, |buf| Test { buf: &buf[0..2] });
+
+        assert_eq!(foo.get().buf, &[0, 1]);
+
+        let owner = foo.into_owner();
+        assert_eq!(&*owner, &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_shared_clone() {
+        let arc: Arc<[u8]> = Arc::from(vec![0, 1, 2, 3].into_boxed_slice());
+        let foo: SharedOwningBytes<&[u8]> = SharedOwningBytes::from_arc(arc, |buf| &buf[0..2]);
+
+        let bar = foo.clone();
+
+        assert_eq!(*foo.get(), &[0, 1]);
+        assert_eq!(*bar.get(), &[0, 1]);
+    }
+
+    #[test]
+    fn test_slice_ref() {
+        let arc: Arc<[u8]> = Arc::from(vec![0, 1, 2, 3].into_boxed_slice());
+        let foo: SharedOwningBytes<()> = SharedOwningBytes::from_arc(arc.clone(), |_| ());
+
+        let subset = &arc[1..3];
+        let sliced = foo.slice_ref(subset).unwrap();
+        assert_eq!(*sliced.get(), &[1, 2]);
+
+        let empty = foo.slice_ref(&[]).unwrap();
+        assert_eq!(*empty.get(), &[] as &[u8]);
+
+        let unrelated = vec![9, 9];
+        assert!(foo.slice_ref(&unrelated).is_none());
+    }
 }